@@ -4,35 +4,105 @@
 // This source code is licensed under the BSD-style license found in the
 // LICENSE file in the root directory of this source tree.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Error, Result};
+use futures::future::join_all;
 use log::{info, warn};
 use protobuf::Message;
+use raft::eraftpb::ConfChange;
 use raft::eraftpb::ConfChangeType;
 use raft::eraftpb::ConfChangeV2;
+use raft::eraftpb::ConfState;
+use raft::eraftpb::Entry;
+use raft::eraftpb::EntryType;
 use raft::eraftpb::Message as RaftMessage;
-use raft::{raw_node::RawNode, storage::MemStorage, Config};
+use raft::eraftpb::Snapshot;
+use raft::{raw_node::RawNode, Config, ReadState, StateRole};
 use slog::{o, Drain};
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::{sleep, Instant};
 use tonic::transport::{Channel, Endpoint};
 use tonic::{Request, Response, Status};
 
+use crate::state_machine::StateMachine;
+use crate::storage::FileStorage;
 use crate::torchftpb::coordinator_service_client::CoordinatorServiceClient;
 use crate::torchftpb::coordinator_service_server::CoordinatorService;
 use crate::torchftpb::{
-    InfoRequest, InfoResponse, NodeInfo, RaftMessageRequest, RaftMessageResponse,
+    ConfChangeRequest, ConfChangeResponse, InfoRequest, InfoResponse, NodeInfo, PromoteRequest,
+    PromoteResponse, ProposeRequest, ProposeResponse, RaftMessageBatchRequest,
+    RaftMessageBatchResponse, RaftMessageRequest, RaftMessageResponse, ReadIndexRequest,
+    ReadIndexResponse,
 };
 
+/// How close (in log entries) a learner's `matched` index must be to the
+/// leader's last log index before `promote` will turn it into a voter.
+/// Promoting a learner that's still far behind would reintroduce the
+/// availability hit that joining as a learner first is meant to avoid.
+const LEARNER_PROMOTION_MAX_LAG: u64 = 1000;
+
+/// How many entries may accumulate past the last snapshot before `tick`
+/// takes a new one and compacts the log. Keeps the WAL (and the amount a
+/// newly joined/lagging follower has to replay) bounded.
+const SNAPSHOT_ENTRIES_THRESHOLD: u64 = 10_000;
+
+/// Cadence of the raft tick loop.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of ticks, after a node reloads persisted state on restart,
+/// during which `tick` holds off advancing Raft's own election clock at
+/// all -- giving the node time to reconnect to peers via `bootstrap` and
+/// hear from an existing leader before it's even eligible to campaign,
+/// so a restart doesn't bump the term and unseat a leader that's still
+/// healthy. This is a separate counter rather than pushing
+/// `randomized_election_timeout` past its normal range, since
+/// `set_randomized_election_timeout` asserts the value stays below
+/// `max_election_timeout` (`2 * election_tick`). ~30s worth of ticks at
+/// the default 100ms cadence.
+const RESTART_ELECTION_GRACE_TICKS: usize = 300;
+
+/// How long `read_index` waits for its `ReadState` to surface (e.g. via a
+/// round of heartbeats confirming leadership) before giving up. Without a
+/// bound, a caller that loses leadership or quorum mid-request would wait
+/// forever and leak its `pending_reads` entry.
+const READ_INDEX_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `wait_for_applied` waits for this node's applied index to
+/// catch up to a `read_index` result before giving up, so a partition
+/// that prevents the local apply loop from progressing doesn't block the
+/// caller forever.
+const WAIT_FOR_APPLIED_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Coordinator {
     rank: u64,
     local_addr: String,
 
-    node: Mutex<RawNode<MemStorage>>,
+    node: Mutex<RawNode<FileStorage>>,
     peers: Mutex<HashMap<u64, NodeInfo>>,
+    state_machine: Arc<dyn StateMachine>,
+    last_snapshot_index: Mutex<u64>,
+    // Remaining ticks of the post-restart election grace period (see
+    // `RESTART_ELECTION_GRACE_TICKS`); zero for a fresh node. Only ever
+    // touched from `tick`, which runs one call at a time, so a plain
+    // atomic (no locking) is enough.
+    restart_grace_ticks: AtomicUsize,
+    // Cached per-peer gRPC clients, reused across ticks instead of
+    // reconnecting on every outbound message. `CoordinatorServiceClient`
+    // wraps a `Channel`, which is cheaply `Clone`-able and multiplexes
+    // requests over the same underlying connection.
+    clients: Mutex<HashMap<u64, CoordinatorServiceClient<Channel>>>,
+
+    // Support for linearizable reads via ReadIndex: `read_seq` hands out a
+    // unique request context for each `read_index` call, and
+    // `pending_reads` holds the completion channel for each outstanding
+    // one until its `ReadState` surfaces in the `tick` loop.
+    read_seq: AtomicU64,
+    pending_reads: Mutex<HashMap<Vec<u8>, oneshot::Sender<u64>>>,
 }
 
 async fn new_coordinator_client(addr: String) -> Result<CoordinatorServiceClient<Channel>> {
@@ -45,7 +115,13 @@ async fn new_coordinator_client(addr: String) -> Result<CoordinatorServiceClient
 }
 
 impl Coordinator {
-    pub fn new(rank: u64, world_size: u64, local_addr: String) -> Self {
+    pub fn new(
+        rank: u64,
+        world_size: u64,
+        local_addr: String,
+        storage_dir: PathBuf,
+        state_machine: Arc<dyn StateMachine>,
+    ) -> Self {
         let config = Config {
             // ids start at 1
             id: rank + 1,
@@ -57,33 +133,79 @@ impl Coordinator {
         // After, make sure it's valid!
         config.validate().unwrap();
 
-        // We don't care about the log so we can use MemStorage
-        let storage = MemStorage::new_with_conf_state((vec![1], vec![]));
+        // Reload any HardState/ConfState/log persisted by a previous run
+        // instead of starting from a blank slate on every restart.
+        let storage = FileStorage::open(&storage_dir).unwrap();
+        let fresh = !storage.is_initialized();
+        let last_snapshot_index = storage.snapshot_index();
+        if last_snapshot_index > 0 {
+            // The log up to the snapshot is gone, so the state machine has
+            // to be brought back from the snapshot's data; only entries
+            // after it are replayed through `tick`.
+            let snapshot = raft::Storage::snapshot(&storage, 0, 0).unwrap();
+            state_machine.restore(snapshot.get_data()).unwrap();
+        }
         let mut node = RawNode::new(&config, storage, &logger).unwrap();
 
-        let steps = (1..world_size + 1)
-            .map(|i| raft_proto::new_conf_change_single(i, ConfChangeType::AddNode))
-            .collect::<Vec<_>>();
-        let mut cc = ConfChangeV2::default();
-        cc.set_changes(steps.into());
-        node.apply_conf_change(&cc).unwrap();
+        let restart_grace_ticks = if fresh {
+            let steps = (1..world_size + 1)
+                .map(|i| raft_proto::new_conf_change_single(i, ConfChangeType::AddNode))
+                .collect::<Vec<_>>();
+            let mut cc = ConfChangeV2::default();
+            cc.set_changes(steps.into());
+            node.apply_conf_change(&cc).unwrap();
+            0
+        } else {
+            info!("reloaded persisted raft state from {:?}", storage_dir);
+
+            // Don't let ourselves campaign the moment we're restarted --
+            // give ourselves a grace period to reconnect to peers via
+            // `bootstrap` and hear from an existing leader first, so a
+            // restart doesn't bump the term and unseat a leader that's
+            // still healthy. See `RESTART_ELECTION_GRACE_TICKS`.
+            RESTART_ELECTION_GRACE_TICKS
+        };
 
         Self {
             rank: rank,
             local_addr: local_addr,
             node: Mutex::new(node),
             peers: Mutex::new(HashMap::new()),
+            state_machine,
+            last_snapshot_index: Mutex::new(last_snapshot_index),
+            clients: Mutex::new(HashMap::new()),
+            read_seq: AtomicU64::new(0),
+            pending_reads: Mutex::new(HashMap::new()),
+            restart_grace_ticks: AtomicUsize::new(restart_grace_ticks),
         }
     }
 
     pub async fn run(self: Arc<Self>) -> Result<()> {
         info!("running raft loop...");
 
-        loop {
-            self.tick().await?;
+        let mut last_tick = Instant::now();
 
-            // TODO: account for tick lag
-            sleep(Duration::from_millis(100)).await;
+        loop {
+            // Account for tick lag: a GC pause or slow message handling
+            // can eat into our budget between iterations, so measure how
+            // much wall-clock time actually elapsed and fire that many
+            // logical ticks. Firing only one tick per iteration regardless
+            // of elapsed time would make Raft's election/heartbeat clocks
+            // run slower than real time whenever we fall behind.
+            let due = (last_tick.elapsed().as_millis() / TICK_INTERVAL.as_millis()).max(1) as u32;
+            for _ in 0..due {
+                self.tick().await?;
+            }
+            last_tick += TICK_INTERVAL * due;
+
+            let now = Instant::now();
+            if last_tick > now {
+                sleep(last_tick - now).await;
+            } else {
+                // Still behind after catching up (tick() itself took a
+                // while) -- resync to now rather than spiraling further.
+                last_tick = now;
+            }
         }
     }
 
@@ -92,7 +214,17 @@ impl Coordinator {
 
         {
             let mut node = self.node.lock().await;
-            node.tick();
+
+            if self.restart_grace_ticks.load(Ordering::Relaxed) > 0 {
+                // Still within the post-restart grace window: hold off
+                // advancing Raft's election clock so we don't campaign
+                // before hearing from an existing leader. Messages that
+                // arrive via `raft_message`/`raft_message_batch` are
+                // still stepped and handled below regardless.
+                self.restart_grace_ticks.fetch_sub(1, Ordering::Relaxed);
+            } else {
+                node.tick();
+            }
 
             if !node.has_ready() {
                 return Ok(());
@@ -105,15 +237,23 @@ impl Coordinator {
                 messages.append(&mut ready.take_messages());
             }
 
+            // Resolve any pending `read_index` calls whose `ReadState` has
+            // now surfaced, so callers waiting on `read_index` learn the
+            // committed index their read should be linearized against.
+            if !ready.read_states().is_empty() {
+                self.resolve_read_states(ready.read_states()).await;
+            }
+
             // 2. Check whether snapshot is empty or not. If not empty, it means
             // that the Raft node has received a Raft snapshot from the leader and
             // we must apply the snapshot:
 
             if !ready.snapshot().is_empty() {
                 // This is a snapshot, we need to apply the snapshot at first.
-                node.mut_store()
-                    .wl()
-                    .apply_snapshot(ready.snapshot().clone())?;
+                let snapshot = ready.snapshot().clone();
+                self.state_machine.restore(snapshot.get_data())?;
+                *self.last_snapshot_index.lock().await = snapshot.get_metadata().get_index();
+                node.mut_store().apply_snapshot(snapshot)?;
             }
 
             // 3. Check whether committed_entries is empty or not. If not, it means
@@ -121,14 +261,15 @@ impl Coordinator {
             // to the state machine. Of course, after applying, you need to update
             // the applied index and resume apply later:
 
-            // TODO: handle committed entries
+            self.apply_committed_entries(&mut *node, ready.take_committed_entries()).await?;
 
             // 4. Check whether entries is empty or not. If not empty, it means that
             // there are newly added entries but have not been committed yet, we
             // must append the entries to the Raft log:
             if !ready.entries().is_empty() {
-                // Append entries to the Raft log
-                node.mut_store().wl().append(ready.entries()).unwrap();
+                // Append entries to the Raft log. This fsyncs to the WAL
+                // before returning.
+                node.mut_store().append(ready.entries()).unwrap();
             }
 
             // 5. Check whether hs is empty or not. If not empty, it means that the
@@ -137,8 +278,13 @@ impl Coordinator {
             // the changed HardState:
 
             if let Some(hs) = ready.hs() {
-                // Raft HardState changed, and we need to persist it.
-                node.mut_store().wl().set_hardstate(hs.clone());
+                // Raft HardState changed, and we need to persist it. This
+                // fsyncs before returning, so by the time we reach step 6
+                // and hand persisted_messages to peers, both the HardState
+                // and the entries above are durable on disk -- a crash
+                // after this point can never un-vote or un-commit what we
+                // just told peers about.
+                node.mut_store().set_hardstate(hs.clone()).unwrap();
             }
 
             // 6. Check whether persisted_messages is empty or not. If not, it means
@@ -157,8 +303,10 @@ impl Coordinator {
             let mut light_rd = node.advance(ready);
             // Like step 1 and 3, you can use functions to make them behave the same.
             messages.append(&mut light_rd.take_messages());
-            //handle_committed_entries(light_rd.take_committed_entries());
+            self.apply_committed_entries(&mut *node, light_rd.take_committed_entries()).await?;
             node.advance_apply();
+
+            self.maybe_snapshot(&mut node).await?;
         }
 
         self.handle_messages(&messages).await?;
@@ -166,7 +314,389 @@ impl Coordinator {
         Ok(())
     }
 
+    /// Applies a batch of newly committed entries to the state machine, in
+    /// order. `EntryConfChange`/`EntryConfChangeV2` entries are applied to
+    /// the Raft node itself (as well as handed to the state machine) so
+    /// membership changes proposed through `add_node`/`remove_node` take
+    /// effect once committed, mirroring how `EntryNormal` entries take
+    /// effect once applied here.
+    async fn apply_committed_entries(
+        &self,
+        node: &mut RawNode<FileStorage>,
+        committed_entries: Vec<Entry>,
+    ) -> Result<()> {
+        for entry in committed_entries {
+            match entry.get_entry_type() {
+                EntryType::EntryNormal => {
+                    if !entry.get_data().is_empty() {
+                        self.state_machine.apply(entry.get_data())?;
+                    }
+                }
+                EntryType::EntryConfChange => {
+                    let mut cc = ConfChange::default();
+                    cc.merge_from_bytes(entry.get_data())?;
+                    let context = cc.get_context().to_vec();
+                    let conf_state = node.apply_conf_change(&cc.into_v2())?;
+                    self.on_conf_change(&conf_state, &context).await?;
+                }
+                EntryType::EntryConfChangeV2 => {
+                    let mut cc = ConfChangeV2::default();
+                    cc.merge_from_bytes(entry.get_data())?;
+                    let context = cc.get_context().to_vec();
+                    let conf_state = node.apply_conf_change(&cc)?;
+                    self.on_conf_change(&conf_state, &context).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles the side effects of a committed membership change: hands
+    /// the new `ConfState` to the state machine, learns the joining
+    /// peer's address from the conf-change context (carried as a
+    /// serialized `NodeInfo` so a follower doesn't need a separate `info`
+    /// round trip to learn it), and drops peers that are no longer part
+    /// of the member set (voters and learners).
+    async fn on_conf_change(&self, conf_state: &ConfState, context: &[u8]) -> Result<()> {
+        self.state_machine.apply_conf_change(conf_state);
+
+        let mut peers = self.peers.lock().await;
+        let mut clients = self.clients.lock().await;
+
+        if !context.is_empty() {
+            if let Ok(node_info) = NodeInfo::parse_from_bytes(context) {
+                if node_info.rank != self.rank {
+                    // A re-added peer may have come back at a different
+                    // address; evict its cached client so `get_client`
+                    // reconnects instead of keeping the stale channel.
+                    let address_changed = peers
+                        .get(&node_info.rank)
+                        .map(|existing| existing.address != node_info.address)
+                        .unwrap_or(false);
+                    if address_changed {
+                        clients.remove(&node_info.rank);
+                    }
+
+                    info!("learned peer address from conf change: {:?}", node_info);
+                    peers.insert(node_info.rank, node_info);
+                }
+            }
+        }
+
+        let member_ranks: HashSet<u64> = conf_state
+            .voters
+            .iter()
+            .chain(conf_state.learners.iter())
+            .map(|id| id - 1)
+            .collect();
+        peers.retain(|rank, _| member_ranks.contains(rank));
+        // A peer that left the member set may rejoin at a different
+        // address later, so its cached client (if any) must go with it.
+        clients.retain(|rank, _| member_ranks.contains(rank));
+
+        Ok(())
+    }
+
+    /// Takes a new snapshot and compacts the log once more than
+    /// `SNAPSHOT_ENTRIES_THRESHOLD` entries have been applied since the
+    /// last one. The snapshot's metadata carries the current `ConfState`
+    /// so a node restoring from it alone (a restart, or a learner
+    /// catching up) reconstructs its peer set without needing any log
+    /// entries older than the snapshot.
+    async fn maybe_snapshot(&self, node: &mut RawNode<FileStorage>) -> Result<()> {
+        let applied = node.raft.raft_log.applied;
+
+        let mut last_snapshot_index = self.last_snapshot_index.lock().await;
+        if applied.saturating_sub(*last_snapshot_index) <= SNAPSHOT_ENTRIES_THRESHOLD {
+            return Ok(());
+        }
+
+        let term = node.raft.raft_log.term(applied)?;
+        let conf_state = node.raft.prs().conf().to_conf_state();
+
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().set_index(applied);
+        snapshot.mut_metadata().set_term(term);
+        snapshot.mut_metadata().set_conf_state(conf_state);
+        snapshot.set_data(self.state_machine.snapshot()?);
+
+        node.mut_store().compact(snapshot)?;
+        *last_snapshot_index = applied;
+
+        info!("took snapshot and compacted log up to index {}", applied);
+
+        Ok(())
+    }
+
+    async fn resolve_read_states(&self, read_states: &[ReadState]) {
+        let mut pending = self.pending_reads.lock().await;
+        for read_state in read_states {
+            if let Some(tx) = pending.remove(&read_state.request_ctx) {
+                let _ = tx.send(read_state.index);
+            }
+        }
+    }
+
+    /// Returns the current committed index the caller may linearize reads
+    /// of the state machine against, without going through the log: it
+    /// asks the leader to confirm it's still the leader via a round of
+    /// heartbeats (`node.read_index`) before returning the index as of
+    /// when the request was issued. If this node isn't the leader, the
+    /// request is forwarded to whichever peer it believes is.
+    ///
+    /// Before returning, it waits (see `wait_for_applied`) until this
+    /// node's own applied index has reached the returned value, so once
+    /// this returns the caller may read the local state machine directly.
+    pub async fn read_index(&self) -> Result<u64> {
+        let (is_leader, leader_id) = {
+            let node = self.node.lock().await;
+            (node.raft.state == StateRole::Leader, node.raft.leader_id)
+        };
+
+        let index = if is_leader {
+            let ctx = self
+                .read_seq
+                .fetch_add(1, Ordering::Relaxed)
+                .to_be_bytes()
+                .to_vec();
+            let (tx, rx) = oneshot::channel();
+            self.pending_reads.lock().await.insert(ctx.clone(), tx);
+
+            {
+                let mut node = self.node.lock().await;
+                node.read_index(ctx.clone());
+            }
+
+            match tokio::time::timeout(READ_INDEX_TIMEOUT, rx).await {
+                Ok(Ok(index)) => index,
+                Ok(Err(_)) => return Err(Error::msg("read_index request was dropped")),
+                Err(_) => {
+                    // Nothing ever resolved the request (e.g. we lost
+                    // leadership, or quorum was never confirmed) -- drop
+                    // the orphaned entry so it doesn't leak forever.
+                    self.pending_reads.lock().await.remove(&ctx);
+                    return Err(Error::msg("read_index timed out waiting for quorum"));
+                }
+            }
+        } else {
+            if leader_id == 0 {
+                return Err(Error::msg("no leader elected yet"));
+            }
+            let mut client = self.get_client(leader_id - 1).await?;
+            let response = client
+                .read_index(Request::new(ReadIndexRequest {}))
+                .await?;
+            response.into_inner().index
+        };
+
+        self.wait_for_applied(index).await?;
+
+        Ok(index)
+    }
+
+    /// Blocks until this node's applied index has reached `index`, so a
+    /// caller of `read_index` only observes the state machine once it's
+    /// actually caught up to the index it was told is safe to read. Gives
+    /// up after `WAIT_FOR_APPLIED_TIMEOUT` rather than blocking forever if
+    /// a partition keeps the local apply loop from ever reaching it.
+    async fn wait_for_applied(&self, index: u64) -> Result<()> {
+        let deadline = Instant::now() + WAIT_FOR_APPLIED_TIMEOUT;
+        loop {
+            {
+                let node = self.node.lock().await;
+                if node.raft.raft_log.applied >= index {
+                    return Ok(());
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::msg(format!(
+                    "timed out waiting for applied index to reach {}",
+                    index
+                )));
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Proposes `data` to be committed to the replicated log and applied
+    /// to the state machine. If this node isn't the leader, the proposal
+    /// is forwarded to whichever peer it believes is.
+    pub async fn propose(&self, data: Vec<u8>) -> Result<()> {
+        let (is_leader, leader_id) = {
+            let node = self.node.lock().await;
+            (node.raft.state == StateRole::Leader, node.raft.leader_id)
+        };
+
+        if is_leader {
+            let mut node = self.node.lock().await;
+            node.propose(vec![], data)?;
+            return Ok(());
+        }
+
+        if leader_id == 0 {
+            return Err(Error::msg("no leader elected yet"));
+        }
+
+        let mut client = self.get_client(leader_id - 1).await?;
+        client
+            .propose(Request::new(ProposeRequest { data }))
+            .await?;
+        Ok(())
+    }
+
+    /// Adds `rank` (reachable at `address`) as a new voting member of the
+    /// cluster. The address is carried in the conf change's `context` so
+    /// that every node applying the change learns it directly, rather
+    /// than requiring a separate `info` round trip to the new peer.
+    pub async fn add_node(&self, rank: u64, address: String) -> Result<()> {
+        let node_info = NodeInfo { rank, address };
+
+        let mut cc = ConfChangeV2::default();
+        cc.set_changes(
+            vec![raft_proto::new_conf_change_single(
+                rank + 1,
+                ConfChangeType::AddNode,
+            )]
+            .into(),
+        );
+        cc.set_context(node_info.write_to_bytes()?);
+
+        self.propose_conf_change(cc).await
+    }
+
+    /// Removes `rank` from the cluster's voting members.
+    pub async fn remove_node(&self, rank: u64) -> Result<()> {
+        let mut cc = ConfChangeV2::default();
+        cc.set_changes(
+            vec![raft_proto::new_conf_change_single(
+                rank + 1,
+                ConfChangeType::RemoveNode,
+            )]
+            .into(),
+        );
+
+        self.propose_conf_change(cc).await
+    }
+
+    /// Adds `rank` as a non-voting learner. Prefer this over `add_node`
+    /// when growing a live cluster: a learner receives log entries and
+    /// advances its match index without counting toward commit, so it
+    /// doesn't cost the cluster a quorum member while it's still catching
+    /// up. Call `promote` once it has caught up.
+    pub async fn add_learner(&self, rank: u64, address: String) -> Result<()> {
+        let node_info = NodeInfo { rank, address };
+
+        let mut cc = ConfChangeV2::default();
+        cc.set_changes(
+            vec![raft_proto::new_conf_change_single(
+                rank + 1,
+                ConfChangeType::AddLearnerNode,
+            )]
+            .into(),
+        );
+        cc.set_context(node_info.write_to_bytes()?);
+
+        self.propose_conf_change(cc).await
+    }
+
+    /// Promotes a learner added via `add_learner` to a full voting
+    /// member. Refuses (on the leader, where per-peer progress is known)
+    /// until the learner's replicated log is within
+    /// `LEARNER_PROMOTION_MAX_LAG` entries of the leader's last index, so
+    /// promotion never hands voting power to a node that's still mostly
+    /// caught up from a snapshot.
+    pub async fn promote(&self, rank: u64) -> Result<()> {
+        let (is_leader, leader_id) = {
+            let node = self.node.lock().await;
+            (node.raft.state == StateRole::Leader, node.raft.leader_id)
+        };
+
+        if is_leader {
+            let node = self.node.lock().await;
+            self.check_learner_caught_up(&node, rank)?;
+            drop(node);
+
+            let mut cc = ConfChangeV2::default();
+            cc.set_changes(
+                vec![raft_proto::new_conf_change_single(
+                    rank + 1,
+                    ConfChangeType::AddNode,
+                )]
+                .into(),
+            );
+            return self.propose_conf_change(cc).await;
+        }
+
+        if leader_id == 0 {
+            return Err(Error::msg("no leader elected yet"));
+        }
+
+        let mut client = self.get_client(leader_id - 1).await?;
+        client.promote(Request::new(PromoteRequest { rank })).await?;
+        Ok(())
+    }
+
+    fn check_learner_caught_up(&self, node: &RawNode<FileStorage>, rank: u64) -> Result<()> {
+        let id = rank + 1;
+        let progress = node
+            .raft
+            .prs()
+            .get(id)
+            .ok_or_else(|| Error::msg(format!("rank {} is not a known peer", rank)))?;
+        let last_index = node.raft.raft_log.last_index();
+
+        if last_index.saturating_sub(progress.matched) > LEARNER_PROMOTION_MAX_LAG {
+            return Err(Error::msg(format!(
+                "rank {} has not caught up yet (matched={}, last_index={})",
+                rank, progress.matched, last_index
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Proposes a `ConfChangeV2` to be committed to the replicated log. If
+    /// this node isn't the leader, the proposal is forwarded to whichever
+    /// peer it believes is, mirroring `propose`.
+    async fn propose_conf_change(&self, cc: ConfChangeV2) -> Result<()> {
+        let (is_leader, leader_id) = {
+            let node = self.node.lock().await;
+            (node.raft.state == StateRole::Leader, node.raft.leader_id)
+        };
+
+        if is_leader {
+            let mut node = self.node.lock().await;
+            node.propose_conf_change(vec![], cc)?;
+            return Ok(());
+        }
+
+        if leader_id == 0 {
+            return Err(Error::msg("no leader elected yet"));
+        }
+
+        let mut client = self.get_client(leader_id - 1).await?;
+        client
+            .conf_change(Request::new(ConfChangeRequest {
+                conf_change: cc.write_to_bytes()?,
+            }))
+            .await?;
+        Ok(())
+    }
+
+    /// Returns a client for `rank`, reusing a cached connection if we
+    /// have one. Connections are only opened lazily, on first use or
+    /// after a previous one is evicted, either because a send through it
+    /// failed or because a membership change removed the peer or changed
+    /// its address.
     async fn get_client(&self, rank: u64) -> Result<CoordinatorServiceClient<Channel>> {
+        {
+            let clients = self.clients.lock().await;
+            if let Some(client) = clients.get(&rank) {
+                return Ok(client.clone());
+            }
+        }
+
         let addr: String = {
             let peers = self.peers.lock().await;
             if !peers.contains_key(&rank) {
@@ -175,27 +705,63 @@ impl Coordinator {
             peers[&rank].address.clone()
         };
 
-        return new_coordinator_client(addr).await;
+        let client = new_coordinator_client(addr).await?;
+
+        self.clients.lock().await.insert(rank, client.clone());
+
+        Ok(client)
+    }
+
+    /// Sends `batch` (all addressed to `rank`) in a single
+    /// `raft_message_batch` RPC rather than one round trip per message.
+    /// Evicts the cached client for `rank` on failure so the next attempt
+    /// reconnects instead of repeatedly hitting the same dead channel.
+    async fn send_batch(&self, rank: u64, batch: Vec<RaftMessage>) -> Result<()> {
+        let mut client = self.get_client(rank).await?;
+
+        let messages = batch
+            .iter()
+            .map(|m| m.write_to_bytes())
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        if let Err(e) = client
+            .raft_message_batch(Request::new(RaftMessageBatchRequest { messages }))
+            .await
+        {
+            self.clients.lock().await.remove(&rank);
+            return Err(Error::msg(format!(
+                "failed to send batch of {} message(s) to rank {}: {}",
+                batch.len(),
+                rank,
+                e
+            )));
+        }
+
+        Ok(())
     }
 
     async fn handle_messages(&self, messages: &[RaftMessage]) -> Result<()> {
+        let mut batches: HashMap<u64, Vec<RaftMessage>> = HashMap::new();
         for message in messages {
-            let rank = message.to - 1;
-            let client = self.get_client(rank).await;
-            if client.is_err() {
-                warn!(
-                    "failed to get client for rank {}, err {:?}",
-                    rank,
-                    client.err()
-                );
-                continue;
-            }
-
-            let request = tonic::Request::new(RaftMessageRequest {
-                message: message.write_to_bytes()?,
-            });
+            batches
+                .entry(message.to - 1)
+                .or_insert_with(Vec::new)
+                .push(message.clone());
+        }
 
-            client.unwrap().raft_message(request).await?;
+        // Send each peer's batch concurrently rather than serializing
+        // sends across peers.
+        let results = join_all(
+            batches
+                .into_iter()
+                .map(|(rank, batch)| self.send_batch(rank, batch)),
+        )
+        .await;
+
+        for result in results {
+            if let Err(e) = result {
+                warn!("{:?}", e);
+            }
         }
 
         Ok(())
@@ -269,6 +835,22 @@ impl CoordinatorService for Arc<Coordinator> {
         Ok(Response::new(reply))
     }
 
+    async fn raft_message_batch(
+        &self,
+        request: Request<RaftMessageBatchRequest>,
+    ) -> Result<Response<RaftMessageBatchResponse>, Status> {
+        let mut node = self.node.lock().await;
+
+        for bytes in request.into_inner().messages {
+            let message = RaftMessage::parse_from_bytes(bytes.as_slice())
+                .map_err(|e| Status::internal(format!("Failed to parse message: {}", e)))?;
+            node.step(message)
+                .map_err(|e| Status::internal(format!("Failed to step state machine: {}", e)))?;
+        }
+
+        Ok(Response::new(RaftMessageBatchResponse {}))
+    }
+
     async fn info(&self, request: Request<InfoRequest>) -> Result<Response<InfoResponse>, Status> {
         info!("got info request: {:?}", request);
 
@@ -285,4 +867,119 @@ impl CoordinatorService for Arc<Coordinator> {
 
         Ok(Response::new(reply)) // Send back our formatted greeting
     }
+
+    async fn propose(
+        &self,
+        request: Request<ProposeRequest>,
+    ) -> Result<Response<ProposeResponse>, Status> {
+        self.propose(request.into_inner().data)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to propose: {}", e)))?;
+
+        Ok(Response::new(ProposeResponse {}))
+    }
+
+    async fn conf_change(
+        &self,
+        request: Request<ConfChangeRequest>,
+    ) -> Result<Response<ConfChangeResponse>, Status> {
+        let cc = ConfChangeV2::parse_from_bytes(&request.into_inner().conf_change)
+            .map_err(|e| Status::internal(format!("Failed to parse conf change: {}", e)))?;
+
+        self.propose_conf_change(cc)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to propose conf change: {}", e)))?;
+
+        Ok(Response::new(ConfChangeResponse {}))
+    }
+
+    async fn promote(
+        &self,
+        request: Request<PromoteRequest>,
+    ) -> Result<Response<PromoteResponse>, Status> {
+        self.promote(request.into_inner().rank)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to promote: {}", e)))?;
+
+        Ok(Response::new(PromoteResponse {}))
+    }
+
+    async fn read_index(
+        &self,
+        _request: Request<ReadIndexRequest>,
+    ) -> Result<Response<ReadIndexResponse>, Status> {
+        let index = self
+            .read_index()
+            .await
+            .map_err(|e| Status::internal(format!("Failed to read index: {}", e)))?;
+
+        Ok(Response::new(ReadIndexResponse { index }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoopStateMachine;
+
+    impl StateMachine for NoopStateMachine {
+        fn apply(&self, _data: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn snapshot(&self) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        fn restore(&self, _data: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // Restarting a node that has persisted a HardState (but never taken a
+    // snapshot) must still take the election grace path -- regressing to
+    // the `fresh` path here is exactly what let a restarted node campaign
+    // immediately and unseat a healthy leader.
+    #[test]
+    fn restart_with_persisted_log_grants_election_grace() {
+        let dir = std::env::temp_dir().join(format!(
+            "torchft-restart-grace-test-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let sm: Arc<dyn StateMachine> = Arc::new(NoopStateMachine);
+
+        let fresh = Coordinator::new(0, 1, "127.0.0.1:0".to_string(), dir.clone(), sm.clone());
+        assert_eq!(fresh.restart_grace_ticks.load(Ordering::Relaxed), 0);
+
+        // Drive enough ticks for the single node to win its election (the
+        // randomized election timeout is always below `2 * election_tick`),
+        // so a HardState gets persisted, the same way a real single-node
+        // cluster would well before ever crossing
+        // `SNAPSHOT_ENTRIES_THRESHOLD`.
+        for _ in 0..2 * Config::default().election_tick {
+            futures::executor::block_on(fresh.tick()).unwrap();
+        }
+        drop(fresh);
+
+        let restarted = Coordinator::new(0, 1, "127.0.0.1:0".to_string(), dir.clone(), sm);
+        assert_eq!(
+            restarted.restart_grace_ticks.load(Ordering::Relaxed),
+            RESTART_ELECTION_GRACE_TICKS
+        );
+
+        // Ticking during the grace window must count down the grace
+        // counter rather than touch Raft's election clock -- before this
+        // fix, the equivalent call pushed `randomized_election_timeout`
+        // past `max_election_timeout` and panicked on every restart.
+        futures::executor::block_on(restarted.tick()).unwrap();
+        assert_eq!(
+            restarted.restart_grace_ticks.load(Ordering::Relaxed),
+            RESTART_ELECTION_GRACE_TICKS - 1
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
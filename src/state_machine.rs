@@ -0,0 +1,35 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+// All rights reserved.
+//
+// This source code is licensed under the BSD-style license found in the
+// LICENSE file in the root directory of this source tree.
+
+use anyhow::Result;
+use raft::eraftpb::ConfState;
+
+/// A user-supplied state machine that committed Raft log entries are
+/// applied to. Implementations are driven exclusively from the `tick`
+/// loop, in log order, so they never need their own locking around
+/// `apply`/`apply_conf_change`.
+pub trait StateMachine: Send + Sync {
+    /// Applies the data of a committed `EntryNormal` proposed via
+    /// `Coordinator::propose`. Empty entries (e.g. the no-op Raft appends
+    /// on a new leader's election) are filtered out before this is called.
+    fn apply(&self, data: &[u8]) -> Result<()>;
+
+    /// Observes a committed membership change once it has been applied to
+    /// the Raft node, so implementations can track the current membership
+    /// (e.g. for snapshot metadata) without rescanning the log. The
+    /// default implementation ignores it.
+    fn apply_conf_change(&self, _conf_state: &ConfState) {}
+
+    /// Serializes the full state machine for inclusion in a Raft
+    /// snapshot, so the log can be compacted up to the applied index this
+    /// snapshot was taken at.
+    fn snapshot(&self) -> Result<Vec<u8>>;
+
+    /// Restores the state machine from a snapshot's data, either one
+    /// generated by `snapshot` on this node in the past or one received
+    /// from the leader because this node fell too far behind the log.
+    fn restore(&self, data: &[u8]) -> Result<()>;
+}
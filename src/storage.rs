@@ -0,0 +1,313 @@
+// Copyright (c) Meta Platforms, Inc. and affiliates.
+// All rights reserved.
+//
+// This source code is licensed under the BSD-style license found in the
+// LICENSE file in the root directory of this source tree.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use protobuf::Message;
+use raft::eraftpb::{ConfState, Entry, HardState, Snapshot};
+use raft::storage::MemStorage;
+use raft::Storage as RaftStorage;
+
+const HARDSTATE_FILE: &str = "hardstate.bin";
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+const LOG_FILE: &str = "log.wal";
+
+/// A file-backed `raft::Storage` implementation that durably persists the
+/// `HardState`, log entries and snapshots to a directory on disk so a
+/// restarted node can reload its state instead of starting from scratch.
+///
+/// Reads are served out of an in-memory `MemStorage` mirror so the hot path
+/// (`entries`, `term`, `first_index`, `last_index`) stays cheap. Every write
+/// is fsync'd to disk before the call that makes it returns, so a crash
+/// never loses state a caller was told is durable. Plain appends and
+/// `HardState` updates reach disk before the mirror; an append that
+/// overwrites part of the log, and a snapshot, update the mirror first and
+/// then rewrite the WAL from it.
+pub struct FileStorage {
+    dir: PathBuf,
+    log: File,
+    mem: MemStorage,
+    was_initialized: bool,
+    // The index of the most recently persisted snapshot, i.e. the real
+    // compaction point -- tracked explicitly rather than derived from
+    // `self.mem.snapshot(..)`, which reports the current commit index,
+    // not the last index actually compacted up to.
+    last_snapshot_index: u64,
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut f = File::create(&tmp)?;
+        f.write_all(bytes)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn read_message<M: Message>(path: &Path) -> Result<Option<M>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bytes = fs::read(path)?;
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(M::parse_from_bytes(&bytes)?))
+}
+
+impl FileStorage {
+    /// Opens (or creates) a durable storage directory, replaying any
+    /// persisted `HardState`, snapshot and log entries into the in-memory
+    /// mirror used to serve reads.
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let snapshot: Option<Snapshot> = read_message(&dir.join(SNAPSHOT_FILE))?;
+        let had_snapshot = snapshot.is_some();
+        let last_snapshot_index = snapshot
+            .as_ref()
+            .map(|s| s.get_metadata().get_index())
+            .unwrap_or(0);
+        let mem = MemStorage::new_with_conf_state(ConfState::default());
+        if let Some(snap) = snapshot {
+            mem.wl().apply_snapshot(snap)?;
+        }
+
+        let log_path = dir.join(LOG_FILE);
+        let mut log = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&log_path)?;
+
+        let mut entries = Self::replay_log(&mut log)?;
+        // A crash between persisting a snapshot and rewriting the WAL
+        // leaves entries the snapshot already covers; skip them.
+        entries.retain(|e| e.get_index() > last_snapshot_index);
+        if !entries.is_empty() {
+            mem.wl().append(&entries)?;
+        }
+
+        let hardstate: Option<HardState> = read_message(&dir.join(HARDSTATE_FILE))?;
+        // Whether this directory already held state from a previous run,
+        // i.e. this is a restart, not a fresh node. `ConfState` is only
+        // ever persisted as part of a snapshot (see `apply_snapshot`), so
+        // a node that restarts before ever taking one would never be
+        // detected as a restart if we went by `ConfState` alone -- go by
+        // whatever got durably written on *every* run instead: a
+        // `HardState` (set on the very first `tick`, well before any
+        // snapshot threshold) or a non-empty log.
+        let was_initialized = had_snapshot || hardstate.is_some() || !entries.is_empty();
+
+        if let Some(hs) = hardstate {
+            mem.wl().set_hardstate(hs);
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            log,
+            mem,
+            was_initialized,
+            last_snapshot_index,
+        })
+    }
+
+    /// True if this storage already holds persisted state from a previous
+    /// run (i.e. this is a restart, not a fresh node).
+    pub fn is_initialized(&self) -> bool {
+        self.was_initialized
+    }
+
+    fn replay_log(log: &mut File) -> Result<Vec<Entry>> {
+        log.seek(SeekFrom::Start(0))?;
+        let mut buf = Vec::new();
+        log.read_to_end(&mut buf)?;
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos + 4 <= buf.len() {
+            let len = u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
+            pos += 4;
+            if pos + len > buf.len() {
+                // Truncated trailing record from a crash mid-write; ignore it.
+                break;
+            }
+            entries.push(Entry::parse_from_bytes(&buf[pos..pos + len])?);
+            pos += len;
+        }
+        Ok(entries)
+    }
+
+    /// Appends `entries` to the write-ahead log, fsyncing before returning
+    /// so the caller may safely treat them as durable. `entries` may
+    /// overwrite an existing suffix of the log (e.g. a follower's
+    /// conflicting uncommitted tail gets replaced once a new leader is
+    /// elected) -- the WAL is otherwise append-only on disk, so that case
+    /// is handled by rewriting it from the surviving prefix plus the new
+    /// entries rather than appending, the same way `apply_snapshot`
+    /// rewrites the WAL around a compaction point. Appending the stale and
+    /// new records side by side would leave `replay_log` producing a
+    /// non-monotonic, corrupt log on the next restart.
+    pub fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        if entries[0].get_index() <= self.mem.last_index()? {
+            self.mem.wl().append(entries)?;
+
+            let first_index = self.mem.first_index()?;
+            let last_index = self.mem.last_index()?;
+            let all_entries = if first_index <= last_index {
+                self.mem.entries(
+                    first_index,
+                    last_index + 1,
+                    None,
+                    raft::GetEntriesContext::empty(false),
+                )?
+            } else {
+                Vec::new()
+            };
+            return self.rewrite_log(&all_entries);
+        }
+
+        for entry in entries {
+            let bytes = entry.write_to_bytes()?;
+            self.log.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            self.log.write_all(&bytes)?;
+        }
+        self.log.sync_all()?;
+
+        self.mem.wl().append(entries)?;
+        Ok(())
+    }
+
+    /// Persists the `HardState`, fsyncing before returning.
+    pub fn set_hardstate(&mut self, hs: HardState) -> Result<()> {
+        write_atomic(&self.dir.join(HARDSTATE_FILE), &hs.write_to_bytes()?)?;
+        self.mem.wl().set_hardstate(hs);
+        Ok(())
+    }
+
+    /// Persists a snapshot (incoming from the leader, or self-generated by
+    /// `compact`) so it's servable via `Storage::snapshot` (e.g. to ship to
+    /// a lagging follower), applies it to the in-memory mirror, and
+    /// rewrites the WAL down to just the entries after it so the log
+    /// doesn't keep growing forever and a restart doesn't replay entries
+    /// the snapshot already supersedes. Entries already committed past the
+    /// snapshot's index are kept, not discarded -- only the prefix the
+    /// snapshot now covers is dropped.
+    pub fn apply_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        let compact_index = snapshot.get_metadata().get_index();
+        let last_index = self.mem.last_index()?;
+        let tail = if compact_index < last_index {
+            self.mem.entries(
+                compact_index + 1,
+                last_index + 1,
+                None,
+                raft::GetEntriesContext::empty(false),
+            )?
+        } else {
+            Vec::new()
+        };
+
+        write_atomic(&self.dir.join(SNAPSHOT_FILE), &snapshot.write_to_bytes()?)?;
+        self.mem.wl().apply_snapshot(snapshot)?;
+        if !tail.is_empty() {
+            self.mem.wl().append(&tail)?;
+        }
+        self.last_snapshot_index = compact_index;
+
+        self.rewrite_log(&tail)
+    }
+
+    /// Atomically swaps the WAL for one containing exactly `entries`, so a
+    /// crash mid-rewrite leaves the previous (still-valid) log in place
+    /// rather than a half-written one.
+    fn rewrite_log(&mut self, entries: &[Entry]) -> Result<()> {
+        let tmp_path = self.dir.join(LOG_FILE).with_extension("tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            for entry in entries {
+                let bytes = entry.write_to_bytes()?;
+                tmp.write_all(&(bytes.len() as u32).to_le_bytes())?;
+                tmp.write_all(&bytes)?;
+            }
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, self.dir.join(LOG_FILE))?;
+        self.log = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(self.dir.join(LOG_FILE))?;
+
+        Ok(())
+    }
+
+    /// Compacts the log up to `snapshot`'s index. Self-generated snapshots
+    /// and incoming ones from the leader need the exact same treatment --
+    /// persist, apply, and truncate the WAL -- so this just delegates to
+    /// `apply_snapshot`.
+    pub fn compact(&mut self, snapshot: Snapshot) -> Result<()> {
+        self.apply_snapshot(snapshot)
+    }
+
+    pub fn conf_state(&self) -> Result<ConfState> {
+        Ok(self.mem.initial_state()?.conf_state)
+    }
+
+    /// The index of the most recently persisted snapshot, or 0 if none has
+    /// ever been taken.
+    pub fn snapshot_index(&self) -> u64 {
+        self.last_snapshot_index
+    }
+}
+
+impl RaftStorage for FileStorage {
+    fn initial_state(&self) -> raft::Result<raft::RaftState> {
+        self.mem.initial_state()
+    }
+
+    fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+        context: raft::GetEntriesContext,
+    ) -> raft::Result<Vec<Entry>> {
+        self.mem.entries(low, high, max_size, context)
+    }
+
+    fn term(&self, idx: u64) -> raft::Result<u64> {
+        self.mem.term(idx)
+    }
+
+    fn first_index(&self) -> raft::Result<u64> {
+        self.mem.first_index()
+    }
+
+    fn last_index(&self) -> raft::Result<u64> {
+        self.mem.last_index()
+    }
+
+    fn snapshot(&self, request_index: u64, to: u64) -> raft::Result<Snapshot> {
+        // Serve the snapshot persisted by `apply_snapshot`, which carries
+        // the state machine's data; the mirror's own snapshot has none.
+        match read_message::<Snapshot>(&self.dir.join(SNAPSHOT_FILE)) {
+            Ok(Some(snapshot)) if snapshot.get_metadata().get_index() >= request_index => {
+                Ok(snapshot)
+            }
+            Ok(_) => self.mem.snapshot(request_index, to),
+            Err(e) => Err(raft::Error::Store(raft::StorageError::Other(e.into()))),
+        }
+    }
+}